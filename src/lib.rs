@@ -1,69 +1,404 @@
-use std::fmt::Debug;
-use std::hash::{Hash, Hasher};
-use std::sync::{Arc, Mutex};
+//! A generator of small, recyclable, thread-safe unique ids, backed by a
+//! bitmap of free ids rather than a monotonically increasing counter.
+//!
+//! # Nightly requirement
+//!
+//! The generic allocator parameter on [`Generator`], [`GeneratorBuilder`]
+//! and [`GeneratorInner`] is built on the standard library's `allocator_api`
+//! feature, which is still unstable with no stabilization timeline. That
+//! means this crate currently requires a nightly toolchain to build at
+//! all, even if you never name a custom allocator and only use the
+//! `Global`-backed defaults.
+#![no_std]
+#![feature(allocator_api)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
+extern crate std;
+
+mod lock;
+
+#[cfg(feature = "std")]
+mod magazine;
+
+use alloc::alloc::{Allocator, Global};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Debug;
+use core::hash::{Hash, Hasher};
+use core::marker::PhantomData;
+
+pub use lock::{DefaultLock, RawLock, SpinLock};
+
+/// Number of ids represented by a single free-bitmap word.
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// Number of `u64` words needed to represent `ids` bits.
+fn word_count(ids: usize) -> usize {
+    ids.div_ceil(BITS_PER_WORD)
+}
+
+/// A mask with only the bits below `ids % BITS_PER_WORD` set, used to blank
+/// out the unused high bits of the last word when `ids` isn't a multiple of
+/// `BITS_PER_WORD`.
+fn tail_mask(ids: usize) -> u64 {
+    let tail_bits = ids % BITS_PER_WORD;
+    if tail_bits == 0 {
+        u64::MAX
+    } else {
+        (1u64 << tail_bits) - 1
+    }
+}
 
-struct GeneratorInner {
-    ids: Vec<usize>,
+/// Controls the order in which freed ids are handed back out by
+/// [`Generator::generate`].
+///
+/// This only affects ids that have actually been freed; brand-new ids drawn
+/// from the bitmap are always handed out lowest-first either way.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ReusePolicy {
+    /// Freed ids are immediately eligible for reuse. This is the default.
+    #[default]
+    Lifo,
+    /// Freed ids are queued and only reissued once every other free id has
+    /// been exhausted, maximizing the interval before a value recurs.
+    Fifo,
+}
+
+/// The state a [`RawLock`] guards for a given [`Generator`].
+///
+/// This has to be `pub` because it appears in the `RawLock<GeneratorInner<A>>`
+/// bound on [`Generator`], [`Id`] and [`GeneratorBuilder::build`] — but its
+/// fields stay private, so it's only nameable, not constructible or
+/// inspectable, outside this crate.
+pub struct GeneratorInner<A: Allocator = Global> {
+    /// Bitmap of free ids: bit `n` of `words[w]` set means id `w * BITS_PER_WORD + n` is free.
+    words: Vec<u64, A>,
+    /// Total number of ids represented by `words` (may be fewer than
+    /// `words.len() * BITS_PER_WORD` if the last word is partially masked).
     allocated: usize,
+    /// Lowest word index that might still contain a free bit.
+    cursor: usize,
+    /// How freed ids are returned to the pool.
+    reuse_policy: ReusePolicy,
+    /// Ids freed under [`ReusePolicy::Fifo`], queued until the bitmap is
+    /// fully exhausted.
+    pending: VecDeque<usize>,
 }
 
-pub struct Generator {
-    inner: Arc<Mutex<GeneratorInner>>,
+/// Take the lowest free id out of `inner`, draining `pending` and growing
+/// the bitmap (in that order) if it's completely full.
+fn take_locked<A: Allocator>(
+    inner: &mut GeneratorInner<A>,
     chunk_size: usize,
+) -> Result<usize, GenerateError> {
+    loop {
+        while inner.cursor < inner.words.len() {
+            let word = inner.words[inner.cursor];
+            if word != 0 {
+                let bit = word.trailing_zeros() as usize;
+                inner.words[inner.cursor] &= !(1u64 << bit);
+                return Ok(inner.cursor * BITS_PER_WORD + bit);
+            }
+            inner.cursor += 1;
+        }
+
+        if let Some(value) = inner.pending.pop_front() {
+            return Ok(value);
+        }
+
+        let words_to_add = (chunk_size / BITS_PER_WORD).max(1);
+
+        inner
+            .words
+            .try_reserve(words_to_add)
+            .map_err(GenerateError::AllocError)?;
+
+        if !inner.allocated.is_multiple_of(BITS_PER_WORD) {
+            // The old tail word had its high bits masked off by `build`
+            // because `allocated` wasn't word-aligned. Those ids are valid
+            // now that the pool is growing past them, so unmask them and
+            // let the cursor revisit that word.
+            let tail_index = inner.words.len() - 1;
+            inner.words[tail_index] |= !tail_mask(inner.allocated);
+            inner.cursor = inner.cursor.min(tail_index);
+        }
+
+        for _ in 0..words_to_add {
+            inner.words.push(u64::MAX);
+        }
+
+        inner.allocated = inner.words.len() * BITS_PER_WORD;
+    }
 }
 
-impl Generator {
-    pub fn generate(&mut self) -> Id {
-        if let Ok(mut inner) = self.inner.lock() {
-            if let Some(value) = inner.ids.pop() {
-                Id {
-                    inner: Arc::new(IdInner {
-                        value,
-                        parent: Arc::clone(&self.inner),
-                    }),
+/// Whether `inner` has an id available without growing the bitmap.
+fn has_free_without_growing<A: Allocator>(inner: &GeneratorInner<A>) -> bool {
+    if !inner.pending.is_empty() {
+        return true;
+    }
+
+    let mut cursor = inner.cursor;
+    while cursor < inner.words.len() {
+        if inner.words[cursor] != 0 {
+            return true;
+        }
+        cursor += 1;
+    }
+
+    false
+}
+
+/// Mark `value` as free in `inner`'s bitmap, rewinding the cursor if needed.
+fn mark_free<A: Allocator>(inner: &mut GeneratorInner<A>, value: usize) {
+    let word = value / BITS_PER_WORD;
+    let bit = value % BITS_PER_WORD;
+
+    inner.words[word] |= 1u64 << bit;
+
+    if word < inner.cursor {
+        inner.cursor = word;
+    }
+}
+
+/// Return `value` to the pool, following `inner`'s [`ReusePolicy`].
+fn free_locked<A: Allocator>(inner: &mut GeneratorInner<A>, value: usize) {
+    match inner.reuse_policy {
+        ReusePolicy::Lifo => mark_free(inner, value),
+        ReusePolicy::Fifo => inner.pending.push_back(value),
+    }
+}
+
+pub struct Generator<
+    L: RawLock<GeneratorInner<A>> = DefaultLock<GeneratorInner<Global>>,
+    A: Allocator = Global,
+> {
+    inner: Arc<L>,
+    chunk_size: usize,
+    /// Size of this generator's local cache of pre-reserved ids. `0` disables
+    /// the cache and every `generate` call takes the shared lock directly.
+    magazine_size: usize,
+    /// This generator's local cache of pre-reserved ids, opportunistically
+    /// refilled from the shared pool up to `magazine_size` at a time.
+    magazine: Vec<usize>,
+    /// Unique, never-reused id for the pool behind `inner`, used to key the
+    /// thread-local magazine cache instead of `inner`'s address. Addresses
+    /// get recycled by the allocator once a pool is dropped, which would
+    /// otherwise let a brand-new, unrelated pool inherit a dead pool's stale
+    /// cache entries.
+    #[cfg(feature = "std")]
+    epoch: u64,
+    /// Copy of the pool's `reuse_policy`, so the thread-local cache can be
+    /// skipped under [`ReusePolicy::Fifo`] without taking the lock: the
+    /// cache has no notion of fairness, so honoring `Fifo` requires routing
+    /// every free through `free_locked`'s `pending` queue instead.
+    #[cfg(feature = "std")]
+    reuse_policy: ReusePolicy,
+    _allocator: PhantomData<A>,
+}
+
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Generator<L, A> {
+    /// Generate a new, unique [`Id`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if the generator's lock is poisoned or if growing the free-id
+    /// pool fails. Use [`Generator::try_generate`] to handle these cases
+    /// instead of panicking.
+    pub fn generate(&mut self) -> Id<L, A> {
+        self.try_generate().expect("Could not generate new id!")
+    }
+
+    /// Generate a new, unique [`Id`], without panicking on lock poisoning or
+    /// allocation failure.
+    pub fn try_generate(&mut self) -> Result<Id<L, A>, GenerateError> {
+        let value = if self.magazine_size == 0 {
+            self.take_one()?
+        } else {
+            match self.magazine.pop().or_else(|| self.take_from_thread_cache()) {
+                Some(value) => value,
+                None => {
+                    self.refill_magazine()?;
+                    self.magazine.pop().expect(
+                        "refill_magazine should have added at least one id or returned an error",
+                    )
                 }
-            } else {
-                let old_allocated = inner.allocated;
-                inner.allocated += self.chunk_size;
+            }
+        };
+
+        Ok(Id {
+            inner: Arc::new(IdInner {
+                value,
+                parent: Arc::clone(&self.inner),
+                #[cfg(feature = "std")]
+                magazine_size: self.magazine_size,
+                #[cfg(feature = "std")]
+                epoch: self.epoch,
+                #[cfg(feature = "std")]
+                reuse_policy: self.reuse_policy,
+                _allocator: PhantomData,
+            }),
+        })
+    }
+
+    /// Pop an id from this thread's cache of ids freed by dropped [`Id`]s
+    /// belonging to the same shared pool, if one exists. This lets ids this
+    /// thread already freed be reused without ever taking the shared lock.
+    ///
+    /// Returns `None` under [`ReusePolicy::Fifo`]: the cache has no notion of
+    /// fairness, so honoring `Fifo` means always going through the shared
+    /// pool's `pending` queue instead.
+    #[cfg(feature = "std")]
+    fn take_from_thread_cache(&self) -> Option<usize> {
+        if self.reuse_policy != ReusePolicy::Lifo {
+            return None;
+        }
+
+        magazine::take(self.epoch)
+    }
+
+    /// `no_std` targets have no such cache; see [`IdInner`]'s `Drop` impl.
+    #[cfg(not(feature = "std"))]
+    fn take_from_thread_cache(&self) -> Option<usize> {
+        None
+    }
+
+    /// Take a single id directly from the shared pool.
+    fn take_one(&mut self) -> Result<usize, GenerateError> {
+        let chunk_size = self.chunk_size;
 
-                let last_index = inner.allocated - 1;
+        self.inner
+            .with_lock(|inner| take_locked(inner, chunk_size))
+            .ok_or(GenerateError::LockPoisoned)?
+    }
 
-                for value in old_allocated..last_index {
-                    inner.ids.push(value);
+    /// Refill this generator's magazine from the shared pool in one locked
+    /// batch, up to `magazine_size` ids.
+    ///
+    /// This only ever takes a single id that requires growing the shared
+    /// pool (which then grows by a whole chunk), to guarantee forward
+    /// progress when it's completely exhausted. It never grows it
+    /// just to top the magazine all the way up, since that would pull ids
+    /// out of the pool that the caller doesn't actually need yet, leaving
+    /// them stranded in the magazine instead of available for reuse.
+    fn refill_magazine(&mut self) -> Result<(), GenerateError> {
+        let chunk_size = self.chunk_size;
+        let magazine_size = self.magazine_size;
+        let magazine = &mut self.magazine;
+
+        self.inner
+            .with_lock(|inner| {
+                while magazine.len() < magazine_size && has_free_without_growing(inner) {
+                    magazine.push(take_locked(inner, chunk_size)?);
                 }
 
-                Id {
-                    inner: Arc::new(IdInner {
-                        value: last_index,
-                        parent: Arc::clone(&self.inner),
-                    }),
+                if magazine.is_empty() {
+                    magazine.push(take_locked(inner, chunk_size)?);
                 }
-            }
-        } else {
-            panic!("Could not generate new id!");
+
+                Ok(())
+            })
+            .ok_or(GenerateError::LockPoisoned)?
+    }
+}
+
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Clone for Generator<L, A> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            chunk_size: self.chunk_size,
+            magazine_size: self.magazine_size,
+            magazine: Vec::new(),
+            #[cfg(feature = "std")]
+            epoch: self.epoch,
+            #[cfg(feature = "std")]
+            reuse_policy: self.reuse_policy,
+            _allocator: PhantomData,
         }
     }
 }
 
-pub struct GeneratorBuilder {
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Drop for Generator<L, A> {
+    fn drop(&mut self) {
+        if !self.magazine.is_empty() {
+            let magazine = &mut self.magazine;
+
+            self.inner.with_lock(|inner| {
+                for value in magazine.drain(..) {
+                    free_locked(inner, value);
+                }
+            });
+        }
+
+        // If this is the last reference to the pool (no other `Generator`
+        // clones and no live `Id`s), it's about to be deallocated; drop this
+        // thread's cache for it instead of leaking an entry for the rest of
+        // the thread's life.
+        #[cfg(feature = "std")]
+        if Arc::strong_count(&self.inner) == 1 {
+            magazine::forget(self.epoch);
+        }
+    }
+}
+
+/// The error returned by [`Generator::try_generate`].
+#[derive(Debug)]
+pub enum GenerateError {
+    /// The generator's lock could not be acquired, e.g. because a
+    /// `std::sync::Mutex` was poisoned by a panicking holder.
+    LockPoisoned,
+    /// Growing the free-id pool to make room for a new chunk of ids failed.
+    AllocError(alloc::collections::TryReserveError),
+}
+
+impl core::fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            GenerateError::LockPoisoned => f.write_str("could not acquire the generator's lock"),
+            GenerateError::AllocError(error) => core::fmt::Display::fmt(error, f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for GenerateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GenerateError::AllocError(error) => Some(error),
+            GenerateError::LockPoisoned => None,
+        }
+    }
+}
+
+pub struct GeneratorBuilder<A: Allocator = Global> {
     chunk_size: usize,
     default_size: usize,
+    magazine_size: usize,
+    reuse_policy: ReusePolicy,
+    allocator: A,
 }
 
-impl Default for GeneratorBuilder {
+impl Default for GeneratorBuilder<Global> {
     fn default() -> Self {
         Self {
             chunk_size: 128,
             default_size: 128,
+            magazine_size: 0,
+            reuse_policy: ReusePolicy::Lifo,
+            allocator: Global,
         }
     }
 }
 
-impl GeneratorBuilder {
+impl GeneratorBuilder<Global> {
     pub fn new() -> Self {
         Default::default()
     }
+}
 
+impl<A: Allocator> GeneratorBuilder<A> {
     pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
         assert!(chunk_size > 0);
         self.chunk_size = chunk_size;
@@ -75,69 +410,199 @@ impl GeneratorBuilder {
         self
     }
 
-    pub fn build(self) -> Generator {
+    /// Give each generator built from this builder a local cache of up to
+    /// `magazine_size` pre-reserved ids, refilled from the shared pool in a
+    /// single locked batch (without forcing the pool to grow beyond what's
+    /// needed). This cuts lock contention for workloads that call
+    /// [`Generator::generate`] from several threads at once. On `std`
+    /// targets, [`Id`]s are also freed through a thread-local cache and
+    /// flushed back to the shared pool in a batch once it reaches
+    /// `magazine_size`, rather than taking the lock on every drop. A
+    /// `magazine_size` of `0` (the default) disables all of this.
+    ///
+    /// The thread-local free cache is skipped under [`ReusePolicy::Fifo`]:
+    /// it has no notion of fairness, so combining it with `magazine_size`
+    /// would let a thread immediately reuse an id it just freed on the same
+    /// thread, ahead of ids freed earlier elsewhere. Under `Fifo`, frees go
+    /// straight back to the shared pool instead, the same as with a
+    /// `magazine_size` of `0`.
+    pub fn with_magazine_size(mut self, magazine_size: usize) -> Self {
+        self.magazine_size = magazine_size;
+        self
+    }
+
+    /// Control the order in which freed ids are reissued. See
+    /// [`ReusePolicy`].
+    pub fn with_reuse_policy(mut self, reuse_policy: ReusePolicy) -> Self {
+        self.reuse_policy = reuse_policy;
+        self
+    }
+
+    /// Back the internal free-id pool with a custom allocator, instead of
+    /// the `Global` one.
+    pub fn with_allocator<A2: Allocator>(self, allocator: A2) -> GeneratorBuilder<A2> {
+        GeneratorBuilder {
+            chunk_size: self.chunk_size,
+            default_size: self.default_size,
+            magazine_size: self.magazine_size,
+            reuse_policy: self.reuse_policy,
+            allocator,
+        }
+    }
+
+    pub fn build<L: RawLock<GeneratorInner<A>>>(self) -> Generator<L, A> {
+        let count = word_count(self.default_size);
+        let mut words = Vec::with_capacity_in(count, self.allocator);
+
+        for index in 0..count {
+            words.push(if index + 1 == count {
+                tail_mask(self.default_size)
+            } else {
+                u64::MAX
+            });
+        }
+
         Generator {
             chunk_size: self.chunk_size,
-            inner: Arc::new(Mutex::new(GeneratorInner {
-                ids: (0..self.default_size).collect(),
+            magazine_size: self.magazine_size,
+            magazine: Vec::with_capacity(self.magazine_size),
+            #[cfg(feature = "std")]
+            epoch: magazine::next_epoch(),
+            #[cfg(feature = "std")]
+            reuse_policy: self.reuse_policy,
+            inner: Arc::new(L::new(GeneratorInner {
+                words,
                 allocated: self.default_size,
+                cursor: 0,
+                reuse_policy: self.reuse_policy,
+                pending: VecDeque::new(),
             })),
+            _allocator: PhantomData,
         }
     }
 }
 
-struct IdInner {
+struct IdInner<L: RawLock<GeneratorInner<A>>, A: Allocator = Global> {
     value: usize,
-    parent: Arc<Mutex<GeneratorInner>>,
+    parent: Arc<L>,
+    /// Copy of the owning generator's `magazine_size` at the time this id
+    /// was issued, so `Drop` knows whether (and when) to batch frees
+    /// through the thread-local magazine instead of taking the shared lock
+    /// directly. Only meaningful on `std`, which is the only target with a
+    /// thread-local cache to batch into.
+    #[cfg(feature = "std")]
+    magazine_size: usize,
+    /// Copy of the owning generator's epoch, keying the thread-local
+    /// magazine cache; see [`Generator`]'s field of the same name.
+    #[cfg(feature = "std")]
+    epoch: u64,
+    /// Copy of the owning generator's `reuse_policy`, so `Drop` can skip the
+    /// thread-local cache under [`ReusePolicy::Fifo`].
+    #[cfg(feature = "std")]
+    reuse_policy: ReusePolicy,
+    _allocator: PhantomData<A>,
 }
 
-impl Drop for IdInner {
+#[cfg(feature = "std")]
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Drop for IdInner<L, A> {
     fn drop(&mut self) {
-        if let Ok(mut parent) = self.parent.lock() {
-            parent.ids.push(self.value);
+        if self.magazine_size == 0 || self.reuse_policy != ReusePolicy::Lifo {
+            self.parent.with_lock(|parent| {
+                free_locked(parent, self.value);
+            });
+            return;
+        }
+
+        let cached = magazine::push(self.epoch, self.value);
+
+        if cached >= self.magazine_size {
+            let values = magazine::drain(self.epoch);
+            self.parent.with_lock(|parent| {
+                for value in values {
+                    free_locked(parent, value);
+                }
+            });
         }
     }
 }
 
-impl Eq for IdInner {}
-impl PartialEq for IdInner {
+/// `no_std` targets have no portable way to attribute a value to "this
+/// thread", so frees always go straight back to the shared pool instead of
+/// through a cache.
+#[cfg(not(feature = "std"))]
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Drop for IdInner<L, A> {
+    fn drop(&mut self) {
+        self.parent.with_lock(|parent| {
+            free_locked(parent, self.value);
+        });
+    }
+}
+
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Eq for IdInner<L, A> {}
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> PartialEq for IdInner<L, A> {
     fn eq(&self, rhs: &Self) -> bool {
         self.value.eq(&rhs.value)
     }
 }
 
-impl Debug for IdInner {
-    fn fmt(
-        &self,
-        format: &mut std::fmt::Formatter<'_>,
-    ) -> std::result::Result<(), std::fmt::Error> {
-        format.write_str(&format!("Id({})", self.value))
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Debug for IdInner<L, A> {
+    fn fmt(&self, format: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        format.write_fmt(format_args!("Id({})", self.value))
     }
 }
 
-impl Hash for IdInner {
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Hash for IdInner<L, A> {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.value.hash(state);
     }
 }
 
-#[derive(Clone, Eq, PartialEq, Debug, Hash)]
-pub struct Id {
-    inner: Arc<IdInner>,
+pub struct Id<
+    L: RawLock<GeneratorInner<A>> = DefaultLock<GeneratorInner<Global>>,
+    A: Allocator = Global,
+> {
+    inner: Arc<IdInner<L, A>>,
+}
+
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Clone for Id<L, A> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Eq for Id<L, A> {}
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> PartialEq for Id<L, A> {
+    fn eq(&self, rhs: &Self) -> bool {
+        self.inner.eq(&rhs.inner)
+    }
+}
+
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Debug for Id<L, A> {
+    fn fmt(&self, format: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Debug::fmt(&self.inner, format)
+    }
+}
+
+impl<L: RawLock<GeneratorInner<A>>, A: Allocator> Hash for Id<L, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.inner.hash(state);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashSet;
+    use alloc::collections::BTreeSet;
 
     #[test]
     fn allocate_ids_no_duplicates() {
-        let mut already_allocated = HashSet::<usize>::new();
+        let mut already_allocated = BTreeSet::<usize>::new();
 
         let size = 1000;
 
-        let mut generator = GeneratorBuilder::new().with_size(size).build();
+        let mut generator: Generator = GeneratorBuilder::new().with_size(size).build();
 
         let mut references = Vec::with_capacity(size);
 
@@ -151,11 +616,11 @@ mod tests {
 
     #[test]
     fn returning_ids_will_allow_them_to_be_reallocated() {
-        let mut already_allocated = HashSet::<usize>::new();
+        let mut already_allocated = BTreeSet::<usize>::new();
 
         let size = 1000;
 
-        let mut generator = GeneratorBuilder::new().with_size(size).build();
+        let mut generator: Generator = GeneratorBuilder::new().with_size(size).build();
 
         let mut references = Vec::with_capacity(size);
 
@@ -179,11 +644,11 @@ mod tests {
 
     #[test]
     fn dont_return_ownership_if_live_reference() {
-        let mut already_allocated = HashSet::<usize>::new();
+        let mut already_allocated = BTreeSet::<usize>::new();
 
         let size = 1000;
 
-        let mut generator = GeneratorBuilder::new().with_size(size).build();
+        let mut generator: Generator = GeneratorBuilder::new().with_size(size).build();
 
         let mut references = Vec::with_capacity(size);
 
@@ -218,4 +683,213 @@ mod tests {
             references.push(val);
         }
     }
+
+    #[test]
+    fn handles_sizes_not_divisible_by_64() {
+        let mut already_allocated = BTreeSet::<usize>::new();
+
+        let size = 100;
+
+        let mut generator: Generator = GeneratorBuilder::new().with_size(size).build();
+
+        let mut references = Vec::with_capacity(size);
+
+        for _ in 0..size {
+            let val = generator.generate();
+            assert!(already_allocated.get(&val.inner.value).is_some() == false);
+            already_allocated.insert(val.inner.value);
+            references.push(val);
+        }
+
+        for id in already_allocated.iter() {
+            assert!(*id < size);
+        }
+    }
+
+    #[test]
+    fn growing_past_a_non_64_aligned_size_recovers_the_old_tail_bits() {
+        let size = 100;
+
+        let mut generator: Generator = GeneratorBuilder::new()
+            .with_size(size)
+            .with_chunk_size(128)
+            .build();
+
+        let mut references = Vec::with_capacity(size + 1);
+        for _ in 0..size {
+            references.push(generator.generate());
+        }
+
+        // Exhausting the initial pool triggers growth; this must not
+        // permanently strand ids 100..128, which were masked off as
+        // out-of-range by `build` but become valid once the pool grows.
+        references.push(generator.generate());
+
+        drop(references);
+
+        let mut seen = BTreeSet::<usize>::new();
+        let mut references = Vec::with_capacity(size + 128);
+        for _ in 0..(size + 128) {
+            let val = generator.generate();
+            seen.insert(val.inner.value);
+            references.push(val);
+        }
+
+        for id in size..128 {
+            assert!(
+                seen.contains(&id),
+                "id {id} in the old tail gap was never reissued"
+            );
+        }
+    }
+
+    #[test]
+    fn magazine_still_yields_unique_ids() {
+        let mut already_allocated = BTreeSet::<usize>::new();
+
+        let size = 1000;
+
+        let mut generator: Generator = GeneratorBuilder::new()
+            .with_size(size)
+            .with_magazine_size(16)
+            .build();
+
+        let mut references = Vec::with_capacity(size);
+
+        for _ in 0..size {
+            let val = generator.generate();
+            assert!(already_allocated.get(&val.inner.value).is_some() == false);
+            already_allocated.insert(val.inner.value);
+            references.push(val);
+        }
+
+        drop(references);
+
+        let mut references = Vec::with_capacity(size);
+
+        for _ in 0..size {
+            let val = generator.generate();
+            assert!(already_allocated.get(&val.inner.value).is_some());
+            references.push(val);
+        }
+    }
+
+    #[test]
+    fn dropping_generator_returns_magazine_to_the_shared_pool() {
+        let size = 64;
+
+        let mut generator: Generator = GeneratorBuilder::new()
+            .with_size(size)
+            .with_magazine_size(16)
+            .build();
+
+        // Prime the magazine without handing out every id in the pool.
+        let val = generator.generate();
+        drop(val);
+
+        let mut clone = generator.clone();
+        drop(generator);
+
+        // Every id in the original pool must still be available without
+        // growing it, including the ones that sat in the dropped magazine.
+        let mut references = Vec::with_capacity(size);
+        for _ in 0..size {
+            let val = clone.generate();
+            assert!(val.inner.value < size, "the pool grew instead of reusing the magazine's ids");
+            references.push(val);
+        }
+    }
+
+    #[test]
+    fn fifo_reuse_policy_delays_id_reuse() {
+        let size = 8;
+
+        let mut generator: Generator = GeneratorBuilder::new()
+            .with_size(size)
+            .with_reuse_policy(ReusePolicy::Fifo)
+            .build();
+
+        let mut references: Vec<_> = (0..4).map(|_| generator.generate()).collect();
+
+        let freed = references.remove(0);
+        let freed_value = freed.inner.value;
+        drop(freed);
+
+        // Ids 4..8 are still free in the bitmap; the freed id must not be
+        // handed back out while any of them remain available.
+        for _ in 0..4 {
+            let next = generator.generate();
+            assert_ne!(next.inner.value, freed_value);
+            references.push(next);
+        }
+
+        // Only once every other free id has been exhausted is the freed id
+        // reissued.
+        let next = generator.generate();
+        assert_eq!(next.inner.value, freed_value);
+    }
+
+    #[test]
+    fn fifo_reuse_policy_is_respected_even_with_a_magazine() {
+        let size = 8;
+
+        let mut generator: Generator = GeneratorBuilder::new()
+            .with_size(size)
+            .with_reuse_policy(ReusePolicy::Fifo)
+            .with_magazine_size(4)
+            .build();
+
+        let mut references: Vec<_> = (0..4).map(|_| generator.generate()).collect();
+
+        let freed = references.remove(0);
+        let freed_value = freed.inner.value;
+        drop(freed);
+
+        // Ids 4..8 are still free; the freed id must not be handed back out
+        // while any of them remain available, even though the generator has
+        // a magazine configured. A buggy implementation that freed through
+        // the thread-local magazine cache instead of `free_locked` would
+        // hand this straight back out on the very next `generate`.
+        for _ in 0..4 {
+            let next = generator.generate();
+            assert_ne!(next.inner.value, freed_value);
+            references.push(next);
+        }
+
+        let next = generator.generate();
+        assert_eq!(next.inner.value, freed_value);
+    }
+
+    #[test]
+    fn fresh_pools_never_inherit_a_dropped_pools_thread_cache() {
+        // Regression test: the thread-local magazine cache used to be keyed
+        // by a pool's `Arc` address, which the allocator can hand to a
+        // brand-new, unrelated pool once the original is fully dropped.
+        // Cycling many small pools through this thread gives the allocator
+        // plenty of chances to reuse an address; if the cache weren't
+        // scoped to each pool's actual lifetime, a later pool's first
+        // `generate` could silently return a value that's also pending
+        // reuse in its own fresh bitmap, handing out the same id twice.
+        for _ in 0..2000 {
+            let mut generator: Generator = GeneratorBuilder::new()
+                .with_size(4)
+                .with_magazine_size(4)
+                .build();
+
+            drop(generator.generate());
+        }
+
+        let mut generator: Generator = GeneratorBuilder::new()
+            .with_size(4)
+            .with_magazine_size(4)
+            .build();
+
+        let mut seen = BTreeSet::<usize>::new();
+        let mut references = Vec::with_capacity(4);
+        for _ in 0..4 {
+            let val = generator.generate();
+            assert!(seen.insert(val.inner.value), "duplicate id handed out");
+            references.push(val);
+        }
+    }
 }
@@ -0,0 +1,81 @@
+//! A minimal locking abstraction so [`crate::Generator`] can run on targets
+//! that don't have `std::sync::Mutex` (e.g. `no_std` + `alloc` targets).
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A primitive lock guarding a value of type `T`.
+///
+/// [`Generator`](crate::Generator) is generic over this trait so the same
+/// id-pooling logic can be backed by whatever mutual-exclusion primitive the
+/// target supports: an OS mutex on `std`, a spinlock on bare-metal, or
+/// anything else an implementor wires up.
+pub trait RawLock<T> {
+    /// Create a new lock guarding `value`.
+    fn new(value: T) -> Self;
+
+    /// Run `f` with exclusive access to the guarded value.
+    ///
+    /// Returns `None` if the lock could not be acquired, e.g. because a
+    /// `std::sync::Mutex` was poisoned by a panicking holder.
+    fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R>;
+}
+
+#[cfg(feature = "std")]
+impl<T> RawLock<T> for std::sync::Mutex<T> {
+    fn new(value: T) -> Self {
+        std::sync::Mutex::new(value)
+    }
+
+    fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.lock().ok().map(|mut guard| f(&mut guard))
+    }
+}
+
+/// A small spinlock `RawLock` implementation for targets without
+/// `std::sync::Mutex`.
+///
+/// This never fails to acquire the lock, so [`RawLock::with_lock`] always
+/// returns `Some`.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> RawLock<T> for SpinLock<T> {
+    fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn with_lock<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+
+        let result = f(unsafe { &mut *self.value.get() });
+
+        self.locked.store(false, Ordering::Release);
+
+        Some(result)
+    }
+}
+
+/// The [`RawLock`] used when no other is specified: `std::sync::Mutex` when
+/// the `std` feature is enabled, or [`SpinLock`] otherwise.
+#[cfg(feature = "std")]
+pub type DefaultLock<T> = std::sync::Mutex<T>;
+
+/// The [`RawLock`] used when no other is specified: `std::sync::Mutex` when
+/// the `std` feature is enabled, or [`SpinLock`] otherwise.
+#[cfg(not(feature = "std"))]
+pub type DefaultLock<T> = SpinLock<T>;
@@ -0,0 +1,54 @@
+//! Thread-local caches backing [`Generator`](crate::Generator)'s magazine
+//! mode on `std` targets.
+//!
+//! Each cache is keyed by the pool's epoch, a value handed out once per pool
+//! by [`next_epoch`] and never reused, rather than by the pool's address.
+//! Addresses get recycled by the allocator once a pool is dropped, which
+//! would otherwise let a brand-new, unrelated pool inherit a dead pool's
+//! stale cache entries; epochs can't collide that way.
+
+use alloc::vec::Vec;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Hand out a new epoch, unique for the life of the process.
+pub(crate) fn next_epoch() -> u64 {
+    NEXT_EPOCH.fetch_add(1, Ordering::Relaxed)
+}
+
+std::thread_local! {
+    static CACHES: RefCell<HashMap<u64, Vec<usize>>> = RefCell::new(HashMap::new());
+}
+
+/// Pop a cached id for `pool` on this thread, if one is available.
+pub(crate) fn take(pool: u64) -> Option<usize> {
+    CACHES.with(|caches| caches.borrow_mut().get_mut(&pool).and_then(Vec::pop))
+}
+
+/// Push `value` into this thread's cache for `pool`, returning the cache's
+/// new length so the caller can decide whether to flush it.
+pub(crate) fn push(pool: u64, value: usize) -> usize {
+    CACHES.with(|caches| {
+        let mut caches = caches.borrow_mut();
+        let cache = caches.entry(pool).or_default();
+        cache.push(value);
+        cache.len()
+    })
+}
+
+/// Remove and return this thread's whole cache for `pool`.
+pub(crate) fn drain(pool: u64) -> Vec<usize> {
+    CACHES.with(|caches| caches.borrow_mut().remove(&pool).unwrap_or_default())
+}
+
+/// Drop this thread's cache for `pool` without returning its contents, for
+/// when the pool itself is about to be destroyed and the cached ids no
+/// longer matter.
+pub(crate) fn forget(pool: u64) {
+    CACHES.with(|caches| {
+        caches.borrow_mut().remove(&pool);
+    });
+}